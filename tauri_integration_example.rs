@@ -1,35 +1,129 @@
-//! Tauriアプリケーションからcsv_to_db.exeを呼び出すサンプルコード
+//! Tauriアプリケーションからcsv_to_dbライブラリを呼び出すサンプルコード
 //!
 //! このファイルは、Tauriプロジェクトの`src-tauri/src/`ディレクトリに配置し、
 //! `main.rs`から`mod csv_to_db;`のように読み込んで使用します。
+//! `csv_to_db`クレートをライブラリとして直接呼び出すため、外部exeの
+//! サブプロセス起動や標準出力の解析は不要。
 
+use csv_to_db::{import, ImportOptions, ImportSummary};
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::process::Command;
-use tauri::{command, State};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tauri::path::BaseDirectory;
+use tauri::{command, AppHandle, Emitter, Manager, State};
+use tokio_util::sync::CancellationToken;
 
-/// csv_to_db.exeの実行結果を格納する構造体
-#[derive(serde::Serialize)]
+/// フロントエンドへ送るインポート進捗イベントの名前
+const PROGRESS_EVENT: &str = "csv_to_db://progress";
+/// インポート完了（成功・失敗・キャンセルいずれも含む）を知らせるイベントの名前
+const DONE_EVENT: &str = "csv_to_db://done";
+/// 同梱のcsv_to_db CLIが見つからない開発時に、その場所を指定するための環境変数名
+const CLI_BIN_ENV_VAR: &str = "CSV_TO_DB_BIN";
+
+/// 実行中のインポートジョブを`job_id`ごとに管理する状態
+pub struct CsvToDbState {
+    jobs: Mutex<HashMap<String, CancellationToken>>,
+    /// スタンドアロン実行用に同梱されたcsv_to_db CLIのパス
+    ///
+    /// 取り込み処理自体はライブラリ呼び出しで完結するため必須ではないが、
+    /// ユーザーがターミナルから直接CLIを叩けるよう、起動時に場所を確定しておく。
+    cli_path: PathBuf,
+}
+
+impl CsvToDbState {
+    fn new(cli_path: PathBuf) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            cli_path,
+        }
+    }
+}
+
+/// ジョブIDの採番用カウンター
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+fn next_job_id() -> String {
+    format!("job-{}", NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// 1ファイルの取り込み結果
+#[derive(Clone, serde::Serialize)]
+pub struct FileImportResult {
+    path: String,
+    records: i64,
+    error: Option<String>,
+}
+
+/// csv_to_dbライブラリの実行結果を格納する構造体
+#[derive(Clone, serde::Serialize)]
 pub struct CsvToDbResult {
     success: bool,
     message: String,
-    records_imported: Option<i32>,
+    records_imported: Option<i64>,
+    files: Vec<FileImportResult>,
+    warnings: Vec<String>,
+    /// ユーザーによってキャンセルされた場合はtrue
+    cancelled: bool,
 }
 
-/// csv_to_db.exeのパスを保持する状態
-pub struct CsvToDbState {
-    exe_path: PathBuf,
+impl From<ImportSummary> for CsvToDbResult {
+    fn from(summary: ImportSummary) -> Self {
+        Self {
+            success: summary.success,
+            message: String::new(),
+            records_imported: Some(summary.records_imported),
+            files: summary
+                .files
+                .into_iter()
+                .map(|f| FileImportResult {
+                    path: f.path,
+                    records: f.records,
+                    error: f.error,
+                })
+                .collect(),
+            warnings: summary.warnings,
+            cancelled: summary.cancelled,
+        }
+    }
 }
 
-impl CsvToDbState {
-    /// 新しい状態を作成
-    pub fn new(exe_path: PathBuf) -> Self {
-        Self { exe_path }
-    }
+/// 1ファイル分のインポートが完了するたびにフロントエンドへ送る進捗情報
+#[derive(Clone, serde::Serialize)]
+pub struct ProgressPayload {
+    current_file: String,
+    files_done: u32,
+    files_total: u32,
+    records_so_far: i64,
 }
 
-/// csv_to_db.exeを実行するTauriコマンド
+/// `csv_to_db://done`イベントのペイロード
+#[derive(Clone, serde::Serialize)]
+struct DonePayload {
+    job_id: String,
+    result: CsvToDbResult,
+}
+
+/// csv_to_dbライブラリでCSVフォルダを取り込むTauriコマンド
+///
+/// ジョブをバックグラウンドで開始し、完了を待たずに`job_id`を即座に返す。
+/// 進捗はファイル単位で`csv_to_db://progress`イベントとして、完了時には
+/// `csv_to_db://done`イベントとして`CsvToDbResult`を通知する。`job_id`は
+/// [`cancel_csv_to_db`]に渡すことでインポートを途中キャンセルできる。
+///
+/// 以前はこのコマンド自身が最終的な`CsvToDbResult`を戻り値として返していたが、
+/// キャンセル対応のためにジョブIDを即時返す形へ変更し、最終結果は
+/// `csv_to_db://done`イベント経由でのみ届く。`CsvToDbResult`が同期的な
+/// 戻り値として得られる、という当初の契約は意図的に廃止された。
+///
+/// `config_path`を指定すると`csv_to_db.toml`のデフォルトメタデータと
+/// ファイル名パターン別のルールが適用される。このコマンドの引数（`plant`、
+/// `machine_id`、`data_label`、`encoding`）は設定ファイルのデフォルト値より
+/// 優先され、さらに設定ファイルのルールにマッチしたファイルはその値が
+/// 両方より優先される。マッチ・優先順位の解決はライブラリ側で行われる。
 #[command]
-pub async fn run_csv_to_db(
+pub fn run_csv_to_db(
+    app_handle: AppHandle,
     state: State<'_, CsvToDbState>,
     folder: String,
     pattern: String,
@@ -38,107 +132,303 @@ pub async fn run_csv_to_db(
     plant: Option<String>,
     machine_id: Option<String>,
     data_label: Option<String>,
-) -> Result<CsvToDbResult, String> {
-    // コマンドを構築
-    let mut cmd = Command::new(state.exe_path.clone());
-    
-    // 必須引数
-    cmd.arg("--folder").arg(folder);
-    cmd.arg("--pattern").arg(pattern);
-    
-    // オプション引数
-    if let Some(db) = db_path {
-        cmd.arg("--db").arg(db);
-    }
-    
-    if let Some(enc) = encoding {
-        cmd.arg("--encoding").arg(enc);
-    }
-    
-    if let Some(p) = plant {
-        cmd.arg("--plant").arg(p);
-    }
-    
-    if let Some(mid) = machine_id {
-        cmd.arg("--machine-id").arg(mid);
-    }
-    
-    if let Some(label) = data_label {
-        cmd.arg("--data-label").arg(label);
-    }
-    
-    // 非同期でコマンドを実行
-    let output = tokio::task::spawn_blocking(move || cmd.output())
-        .await
-        .map_err(|e| format!("タスク実行エラー: {}", e))?
-        .map_err(|e| format!("コマンド実行エラー: {}", e))?;
-    
-    // 実行結果を解析
-    let success = output.status.success();
-    let message = if success {
-        String::from_utf8_lossy(&output.stdout).to_string()
-    } else {
-        String::from_utf8_lossy(&output.stderr).to_string()
+    config_path: Option<String>,
+) -> Result<String, String> {
+    let job_id = next_job_id();
+    let token = CancellationToken::new();
+    state
+        .jobs
+        .lock()
+        .unwrap()
+        .insert(job_id.clone(), token.clone());
+
+    let options = ImportOptions {
+        folder,
+        pattern,
+        db_path,
+        encoding,
+        plant,
+        machine_id,
+        data_label,
+        config_path,
     };
-    
-    // インポートされたレコード数を抽出（成功時のみ）
-    let records_imported = if success {
-        // 出力からインポートされたレコード数を抽出
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        stdout
-            .lines()
-            .find(|line| line.contains("インポートされたレコード数:"))
-            .and_then(|line| {
-                line.split(':')
-                    .nth(1)
-                    .and_then(|s| s.trim().parse::<i32>().ok())
+
+    let job_id_for_task = job_id.clone();
+    tauri::async_runtime::spawn(async move {
+        let progress_handle = app_handle.clone();
+        let cancel_token = token.clone();
+        let import_result = tokio::task::spawn_blocking(move || {
+            import(options, move |progress| {
+                let _ = progress_handle.emit(
+                    PROGRESS_EVENT,
+                    ProgressPayload {
+                        current_file: progress.current_file,
+                        files_done: progress.files_done,
+                        files_total: progress.files_total,
+                        records_so_far: progress.records_so_far,
+                    },
+                );
+                // ファイルの区切りごとにキャンセル要求を確認し、
+                // 要求があればインポートを打ち切る（falseで継続を止める）
+                !cancel_token.is_cancelled()
             })
-    } else {
-        None
-    };
-    
-    Ok(CsvToDbResult {
-        success,
-        message,
-        records_imported,
-    })
+        })
+        .await;
+
+        let result = match import_result {
+            Ok(Ok(summary)) => CsvToDbResult::from(summary),
+            Ok(Err(e)) => CsvToDbResult {
+                success: false,
+                message: e.to_string(),
+                records_imported: None,
+                files: Vec::new(),
+                warnings: Vec::new(),
+                cancelled: false,
+            },
+            Err(e) => CsvToDbResult {
+                success: false,
+                message: format!("タスク実行エラー: {}", e),
+                records_imported: None,
+                files: Vec::new(),
+                warnings: Vec::new(),
+                cancelled: false,
+            },
+        };
+
+        let _ = app_handle.emit(
+            DONE_EVENT,
+            DonePayload {
+                job_id: job_id_for_task.clone(),
+                result,
+            },
+        );
+
+        if let Some(state) = app_handle.try_state::<CsvToDbState>() {
+            state.jobs.lock().unwrap().remove(&job_id_for_task);
+        }
+    });
+
+    Ok(job_id)
+}
+
+/// 実行中のインポートジョブをキャンセルするTauriコマンド
+///
+/// 実際の中断はファイルの区切りで行われるため、キャンセル後も直前まで
+/// コミットされたファイルの取り込みは有効なまま残る。
+#[command]
+pub fn cancel_csv_to_db(state: State<'_, CsvToDbState>, job_id: String) -> Result<(), String> {
+    cancel_job(&state.jobs, &job_id)
+}
+
+/// `cancel_csv_to_db`本体。Tauriの`State`に依存しないため単体テストできる。
+fn cancel_job(jobs: &Mutex<HashMap<String, CancellationToken>>, job_id: &str) -> Result<(), String> {
+    let jobs = jobs.lock().unwrap();
+    match jobs.get(job_id) {
+        Some(token) => {
+            token.cancel();
+            Ok(())
+        }
+        None => Err(format!("ジョブが見つかりません: {}", job_id)),
+    }
+}
+
+/// 同梱されたcsv_to_db CLIのパスをフロントエンドへ返すTauriコマンド
+///
+/// ユーザーがターミナルから直接CLIを叩けるよう、設定画面などに
+/// 実体のパスを表示するために使う。
+#[command]
+pub fn csv_to_db_cli_path(state: State<'_, CsvToDbState>) -> Result<String, String> {
+    state
+        .cli_path
+        .to_str()
+        .map(str::to_string)
+        .ok_or_else(|| "csv_to_db CLIのパスがUTF-8として不正です".to_string())
 }
 
 /// Tauriアプリケーションのmain.rsでの使用例
+///
+/// 同梱されたcsv_to_db CLIの場所を、ハードコードされたパスではなく
+/// Tauriのリソース解決機構（パッケージ後はバンドル同梱のサイドカーを指す）
+/// から特定する。同梱のCLIが無い開発ビルドでは、`CSV_TO_DB_BIN`環境変数で
+/// 指定したパスがフォールバックとして使われる。
+/// CLIが見つからない場合は、初回コマンド実行時ではなくここで即座にエラーにする。
 pub fn setup_csv_to_db(app: &mut tauri::App) -> Result<(), Box<dyn std::error::Error>> {
-    // exe_pathは実際の環境に合わせて設定
-    let exe_path = PathBuf::from("path/to/csv_to_db.exe");
-    
-    // 状態を管理
-    app.manage(CsvToDbState::new(exe_path));
-    
+    let cli_path = resolve_cli_path(app.handle())?;
+    app.manage(CsvToDbState::new(cli_path));
     Ok(())
 }
 
-/// フロントエンドからの呼び出し例（JavaScript/TypeScript）
-///
-/// ```typescript
-/// // Tauriアプリケーションのフロントエンド（JavaScript/TypeScript）
-/// import { invoke } from '@tauri-apps/api/tauri';
+/// csv_to_db CLIの実行ファイルパスを解決する
 ///
-/// async function runCsvToDb() {
-///   try {
-///     const result = await invoke('run_csv_to_db', {
-///       folder: 'C:/path/to/csv_files',
-///       pattern: '*.csv',
-///       dbPath: 'output.duckdb',
-///       encoding: 'utf-8',
-///       plant: 'AAA',
-///       machineId: 'No.1',
-///       dataLabel: '2024'
-///     });
-///     
-///     if (result.success) {
-///       console.log(`成功: ${result.records_imported} レコードがインポートされました`);
-///     } else {
-///       console.error(`エラー: ${result.message}`);
-///     }
-///   } catch (error) {
-///     console.error(`呼び出しエラー: ${error}`);
-///   }
-/// }
+/// パッケージ後に同梱されるサイドカーを優先して解決し、それが失敗した
+/// 場合に限って開発用の`CSV_TO_DB_BIN`環境変数をフォールバックとして使う。
+/// 逆順にすると、開発シェルやCI由来の`CSV_TO_DB_BIN`が残っているだけで
+/// 正しくバンドルされたサイドカーより優先されてしまう。
+fn resolve_cli_path(app: &AppHandle) -> Result<PathBuf, String> {
+    let binary_name = if cfg!(windows) {
+        "csv_to_db.exe"
+    } else {
+        "csv_to_db"
+    };
+
+    resolve_cli_path_with(
+        || {
+            let path = app
+                .path()
+                .resolve(binary_name, BaseDirectory::Resource)
+                .map_err(|e| format!("csv_to_db CLIの解決に失敗しました: {}", e))?;
+            // resolveはパスを組み立てるだけなので、実体の有無はここで確認する
+            if path.exists() {
+                Ok(path)
+            } else {
+                Err(format!("同梱のcsv_to_db CLIが見つかりません: {}", path.display()))
+            }
+        },
+        std::env::var(CLI_BIN_ENV_VAR).ok(),
+    )
+}
+
+/// `resolve_cli_path`本体。サイドカー解決を呼び出し元から注入できるため、
+/// Tauriの`AppHandle`なしに優先順位（リソース解決 > `CSV_TO_DB_BIN`）を
+/// 単体テストできる。
+fn resolve_cli_path_with(
+    resolve_resource: impl FnOnce() -> Result<PathBuf, String>,
+    env_override: Option<String>,
+) -> Result<PathBuf, String> {
+    if let Ok(path) = resolve_resource() {
+        return Ok(path);
+    }
+
+    match env_override {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            if path.exists() {
+                Ok(path)
+            } else {
+                Err(format!(
+                    "{}で指定されたcsv_to_db CLIが見つかりません: {}",
+                    CLI_BIN_ENV_VAR,
+                    path.display()
+                ))
+            }
+        }
+        None => Err(format!(
+            "csv_to_db CLIが見つかりません（リソース解決に失敗し、{}も未設定です）",
+            CLI_BIN_ENV_VAR
+        )),
+    }
+}
+
+// `csv_to_db.toml`の設定例
+//
+// ```toml
+// # デフォルトのメタデータ（CLI引数で指定されなかった場合に使われる）
+// plant = "AAA"
+// machine_id = "No.1"
+// data_label = "2024"
+// encoding = "utf-8"
+//
+// # ファイル名パターンごとのメタデータ上書きルール（先頭から順にマッチ）
+// [[rule]]
+// pattern = "LineA_*.csv"
+// plant = "AAA"
+// machine_id = "No.1"
+//
+// [[rule]]
+// pattern = "LineB_*.csv"
+// plant = "AAA"
+// machine_id = "No.2"
+// ```
+//
+// フロントエンドからの呼び出し例（JavaScript/TypeScript）
+//
+// ```typescript
+// // Tauriアプリケーションのフロントエンド（JavaScript/TypeScript）
+// import { invoke } from '@tauri-apps/api/core';
+// import { listen } from '@tauri-apps/api/event';
+//
+// async function runCsvToDb() {
+//   const unlistenProgress = await listen('csv_to_db://progress', (event) => {
+//     console.log(`進捗: ${event.payload.files_done}/${event.payload.files_total}`);
+//   });
+//   const unlistenDone = await listen('csv_to_db://done', (event) => {
+//     const { job_id, result } = event.payload;
+//     if (result.cancelled) {
+//       console.log(`${job_id} はキャンセルされました`);
+//     } else if (result.success) {
+//       console.log(`成功: ${result.records_imported} レコードがインポートされました`);
+//     } else {
+//       console.error(`エラー: ${result.message}`);
+//     }
+//     unlistenProgress();
+//     unlistenDone();
+//   });
+//
+//   const jobId = await invoke('run_csv_to_db', {
+//     folder: 'C:/path/to/csv_files',
+//     pattern: '*.csv',
+//     dbPath: 'output.duckdb',
+//     encoding: 'utf-8',
+//     plant: 'AAA',
+//     machineId: 'No.1',
+//     dataLabel: '2024',
+//     configPath: 'C:/path/to/csv_to_db.toml'
+//   });
+//
+//   // ユーザーが中止ボタンを押したら:
+//   // await invoke('cancel_csv_to_db', { jobId });
+// }
+// ```
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_job_cancels_a_registered_job() {
+        let jobs = Mutex::new(HashMap::new());
+        let token = CancellationToken::new();
+        jobs.lock().unwrap().insert("job-1".to_string(), token.clone());
+
+        assert!(cancel_job(&jobs, "job-1").is_ok());
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn cancel_job_rejects_an_unknown_job_id() {
+        let jobs = Mutex::new(HashMap::new());
+
+        assert!(cancel_job(&jobs, "no-such-job").is_err());
+    }
+
+    #[test]
+    fn resolve_cli_path_with_prefers_resource_resolution_over_env_var() {
+        let resolved = resolve_cli_path_with(
+            || Ok(PathBuf::from("/resource/csv_to_db")),
+            Some("/dev/csv_to_db".to_string()),
+        );
+
+        assert_eq!(resolved.unwrap(), PathBuf::from("/resource/csv_to_db"));
+    }
+
+    #[test]
+    fn resolve_cli_path_with_falls_back_to_env_var_when_resource_resolution_fails() {
+        let fallback = std::env::temp_dir().join("csv_to_db_resolve_cli_path_test_bin");
+        std::fs::write(&fallback, b"").unwrap();
+
+        let resolved = resolve_cli_path_with(
+            || Err("resource not found".to_string()),
+            Some(fallback.to_string_lossy().to_string()),
+        );
+
+        assert_eq!(resolved.unwrap(), fallback);
+        std::fs::remove_file(&fallback).unwrap();
+    }
+
+    #[test]
+    fn resolve_cli_path_with_errors_when_neither_source_resolves() {
+        let resolved = resolve_cli_path_with(|| Err("resource not found".to_string()), None);
+
+        assert!(resolved.is_err());
+    }
+}